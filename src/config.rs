@@ -1,13 +1,17 @@
 //! Configuration of git-global.
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use app_dirs::{app_dir, get_app_dir, AppDataType, AppInfo};
 use dirs::home_dir;
 use git2;
-use walkdir::{DirEntry, WalkDir};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use jwalk::{Parallelism, WalkDir};
 
 use core::Repo;
 
@@ -18,12 +22,31 @@ const APP: AppInfo = AppInfo {
 const CACHE_FILE: &'static str = "repos.txt";
 const SETTING_BASEDIR: &'static str = "global.basedir";
 const SETTING_IGNORED: &'static str = "global.ignore";
+const SETTING_CACHETTL: &'static str = "global.cachettl";
+const SETTING_SCANTHREADS: &'static str = "global.scanthreads";
+const SETTING_MAXDEPTH: &'static str = "global.maxdepth";
 
 /// A container for git-global configuration options.
 pub struct GitGlobalConfig {
     pub basedir: String,
     pub ignored_patterns: Vec<String>,
     pub cache_file: PathBuf,
+    /// Number of seconds a cache file is considered fresh; `0` means the
+    /// cache never expires on its own.
+    pub cache_ttl: u64,
+    /// Number of worker threads used to scan the filesystem for repos.
+    pub scan_threads: usize,
+    /// Maximum directory depth `find_repos` will descend into, relative to
+    /// `basedir`; `0` means unbounded.
+    pub max_depth: usize,
+    /// Compiled gitignore-style matcher for `global.ignore` patterns plus
+    /// git's own `core.excludesFile`.
+    ignore_matcher: Gitignore,
+    /// Per-repo `.git/info/exclude` matchers, keyed by repo root and built
+    /// lazily the first time an entry under that root is filtered, so a
+    /// scan compiles each repo's exclude file once instead of once per
+    /// entry.
+    repo_exclude_cache: Mutex<HashMap<PathBuf, Option<Gitignore>>>,
 }
 
 impl GitGlobalConfig {
@@ -33,18 +56,38 @@ impl GitGlobalConfig {
             .to_str()
             .expect("Could not convert home directory path to string.")
             .to_string();
-        let (basedir, patterns) = match git2::Config::open_default() {
-            Ok(config) => (
-                config.get_string(SETTING_BASEDIR).unwrap_or(home_dir),
-                config
-                    .get_string(SETTING_IGNORED)
-                    .unwrap_or(String::new())
-                    .split(",")
-                    .map(|p| p.trim().to_string())
-                    .collect(),
-            ),
-            Err(_) => (home_dir, Vec::new()),
-        };
+        let default_scan_threads = num_cpus::get();
+        let (basedir, patterns, cache_ttl, scan_threads, max_depth) =
+            match git2::Config::open_default() {
+                Ok(config) => (
+                    config.get_string(SETTING_BASEDIR).unwrap_or(home_dir),
+                    config
+                        .get_string(SETTING_IGNORED)
+                        .unwrap_or(String::new())
+                        .split(",")
+                        .map(|p| p.trim().to_string())
+                        .collect(),
+                    config
+                        .get_i64(SETTING_CACHETTL)
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .map(|n| n as u64)
+                        .unwrap_or(0),
+                    config
+                        .get_i64(SETTING_SCANTHREADS)
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .map(|n| n as usize)
+                        .unwrap_or(default_scan_threads),
+                    config
+                        .get_i64(SETTING_MAXDEPTH)
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .map(|n| n as usize)
+                        .unwrap_or(0),
+                ),
+                Err(_) => (home_dir, Vec::new(), 0, default_scan_threads, 0),
+            };
         let cache_file =
             match get_app_dir(AppDataType::UserCache, &APP, "cache") {
                 Ok(mut dir) => {
@@ -53,21 +96,100 @@ impl GitGlobalConfig {
                 }
                 Err(_) => panic!("TODO: work without XDG"),
             };
+        let excludes_file = git2::Config::open_default()
+            .ok()
+            .and_then(|config| config.get_path("core.excludesFile").ok());
+        let ignore_matcher =
+            GitGlobalConfig::build_ignore_matcher(&basedir, &patterns, excludes_file);
         GitGlobalConfig {
             basedir: basedir,
             ignored_patterns: patterns,
             cache_file: cache_file,
+            cache_ttl: cache_ttl,
+            scan_threads: scan_threads,
+            max_depth: max_depth,
+            ignore_matcher: ignore_matcher,
+            repo_exclude_cache: Mutex::new(HashMap::new()),
         }
     }
 
+    /// Builds the gitignore-style matcher used by `filter()`, from the
+    /// `global.ignore` patterns plus an optional `excludes_file` (git's own
+    /// `core.excludesFile`, resolved by the caller). Taking the excludes
+    /// file as a parameter rather than reading `git2::Config::open_default()`
+    /// here keeps this testable without depending on the host's ambient git
+    /// config: tests can simply pass `None`.
+    fn build_ignore_matcher(
+        basedir: &str,
+        patterns: &Vec<String>,
+        excludes_file: Option<PathBuf>,
+    ) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(basedir);
+        for pattern in patterns.iter().filter(|p| !p.is_empty()) {
+            let _ = builder.add_line(None, pattern);
+        }
+        if let Some(excludes_file) = excludes_file {
+            builder.add(excludes_file);
+        }
+        builder.build().unwrap_or_else(|_| {
+            GitignoreBuilder::new(basedir)
+                .build()
+                .expect("Could not build an empty gitignore matcher.")
+        })
+    }
+
     /// Returns `true` if this directory entry should be included in scans.
-    pub fn filter(&self, entry: &DirEntry) -> bool {
-        let entry_path = entry.path().to_str().expect("DirEntry without path.");
+    ///
+    /// An entry is excluded if it matches a `global.ignore` pattern or one
+    /// of git's own exclusion sources: `core.excludesFile`, or the
+    /// `.git/info/exclude` of whichever repo (if any) the entry lives in.
+    pub fn filter(&self, path: &Path, is_dir: bool) -> bool {
+        if self
+            .ignore_matcher
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return false;
+        }
+        if let Some(repo_root) = GitGlobalConfig::find_repo_root(path) {
+            let mut cache = self
+                .repo_exclude_cache
+                .lock()
+                .expect("repo exclude cache lock was poisoned");
+            let repo_matcher = cache
+                .entry(repo_root.clone())
+                .or_insert_with(|| GitGlobalConfig::build_repo_exclude_matcher(&repo_root));
+            if let Some(repo_matcher) = repo_matcher {
+                if repo_matcher
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore()
+                {
+                    return false;
+                }
+            }
+        }
+        true
+    }
 
-        self.ignored_patterns
-            .iter()
-            .filter(|p| p != &"")
-            .fold(true, |acc, pattern| acc && !entry_path.contains(pattern))
+    /// Walks up from `path` looking for the repo root (the directory
+    /// holding a `.git`) it lives under, if any.
+    fn find_repo_root(path: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .find(|ancestor| ancestor.join(".git").is_dir())
+            .map(|ancestor| ancestor.to_path_buf())
+    }
+
+    /// Compiles `repo_root`'s `.git/info/exclude` into a matcher, or
+    /// returns `None` if the repo has no exclude file. Called once per
+    /// repo root and cached by `filter()`.
+    fn build_repo_exclude_matcher(repo_root: &Path) -> Option<Gitignore> {
+        let exclude_file = repo_root.join(".git").join("info").join("exclude");
+        if !exclude_file.is_file() {
+            return None;
+        }
+        let mut builder = GitignoreBuilder::new(repo_root);
+        builder.add(exclude_file);
+        builder.build().ok()
     }
 
     /// Returns boolean indicating if the cache file exists.
@@ -75,6 +197,28 @@ impl GitGlobalConfig {
         self.cache_file.as_path().exists()
     }
 
+    /// Returns `true` if the cache file exists and is still within the
+    /// `global.cachettl` window, so callers can skip a rescan. A TTL of `0`
+    /// means the cache never expires on its own.
+    pub fn has_fresh_cache(&self) -> bool {
+        if !self.has_cache() {
+            return false;
+        }
+        if self.cache_ttl == 0 {
+            return true;
+        }
+        let modified = match std::fs::metadata(&self.cache_file)
+            .and_then(|metadata| metadata.modified())
+        {
+            Ok(modified) => modified,
+            Err(_) => return false,
+        };
+        match SystemTime::now().duration_since(modified) {
+            Ok(age) => age.as_secs() < self.cache_ttl,
+            Err(_) => true, // cache file's mtime is in the future; assume fresh
+        }
+    }
+
     /// Writes the given repo paths to the cache file.
     pub fn cache_repos(&self, repos: &Vec<Repo>) {
         if !self.cache_file.as_path().exists() {
@@ -95,6 +239,29 @@ impl GitGlobalConfig {
         }
     }
 
+    /// Returns cached repos whose final path component matches `name`,
+    /// without ever triggering a full `find_repos` scan.
+    ///
+    /// By default this requires an exact match on the last path segment
+    /// (e.g. `name` of `"foo"` matches `~/code/foo` but not
+    /// `~/code/foobar`). Pass `fuzzy: true` to match `name` as a substring
+    /// of that last segment instead.
+    pub fn find_repos_by_name(&self, name: &str, fuzzy: bool) -> Vec<Repo> {
+        self.get_cached_repos()
+            .into_iter()
+            .filter(|repo| match Path::new(repo.path()).file_name().and_then(|f| f.to_str()) {
+                Some(repo_name) => {
+                    if fuzzy {
+                        repo_name.contains(name)
+                    } else {
+                        repo_name == name
+                    }
+                }
+                None => false,
+            })
+            .collect()
+    }
+
     /// Returns the list of repos found in the cache file.
     pub fn get_cached_repos(&self) -> Vec<Repo> {
         let mut repos = Vec::new();
@@ -114,36 +281,48 @@ impl GitGlobalConfig {
 }
 
 /// Walks the configured base directory, looking for git repos.
+///
+/// Uses `jwalk` instead of a serial `WalkDir` so the expensive part of the
+/// scan -- the stat calls and `GitGlobalConfig::filter` matching that
+/// decide which subtrees to prune -- runs across a `global.scanthreads`
+/// sized pool via `process_read_dir`. The final ".git" check runs on the
+/// calling thread as the walk's results are consumed.
+/// `filter` still prunes ignored subtrees before `jwalk` descends into
+/// them, same as the old `filter_entry` semantics.
 pub fn find_repos(config: &GitGlobalConfig) -> Vec<Repo> {
-    let mut repos = Vec::new();
     let basedir = &config.basedir;
 
     println!(
         "Scanning for git repos under {}; this may take a while...",
         basedir
     );
-    for entry in WalkDir::new(basedir)
-        .into_iter()
-        .filter_entry(|e| config.filter(e))
-    {
-        match entry {
-            Ok(entry) => {
-                if entry.file_type().is_dir() && entry.file_name() == ".git" {
-                    let parent_path = entry
-                        .path()
-                        .parent()
-                        .expect("Could not determine parent.");
-                    match parent_path.to_str() {
-                        Some(path) => {
-                            repos.push(Repo::new(path.to_string()));
-                        }
-                        None => (),
-                    }
-                }
-            }
-            Err(_) => (),
-        }
+
+    let mut walker = WalkDir::new(basedir)
+        .parallelism(Parallelism::RayonNewPool(config.scan_threads))
+        .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+            children.retain(|entry| {
+                entry
+                    .as_ref()
+                    .map(|entry| config.filter(&entry.path(), entry.file_type().is_dir()))
+                    .unwrap_or(false)
+            });
+        });
+    if config.max_depth > 0 {
+        walker = walker.max_depth(config.max_depth);
     }
+
+    let mut repos: Vec<Repo> = walker
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_dir() && entry.file_name() == ".git")
+        .filter_map(|entry| {
+            entry
+                .path()
+                .parent()
+                .and_then(|parent| parent.to_str().map(|path| Repo::new(path.to_string())))
+        })
+        .collect();
+
     repos.sort_by(|a, b| a.path().cmp(&b.path()));
     repos
 }
@@ -155,11 +334,220 @@ pub fn cache_repos(config: &mut GitGlobalConfig, repos: &Vec<Repo>) {
 
 /// Returns all known git repos, populating the cache first, if necessary.
 pub fn get_repos(config: &mut GitGlobalConfig) -> Vec<Repo> {
-    if !config.has_cache() {
+    if !config.has_fresh_cache() {
         let repos = find_repos(config);
         cache_repos(config, &repos);
         repos
     } else {
         config.get_cached_repos()
     }
+}
+
+/// Refreshes the cache in place rather than rebuilding it from scratch.
+///
+/// Cached repos whose `.git` directory no longer exists on disk are
+/// dropped, a fresh `find_repos` scan (bounded by `global.maxdepth`, if
+/// set) picks up anything new, and the union is written back to
+/// `repos.txt`. Returns `(added, removed)` so a caller can report
+/// something like "3 new repos, 1 gone".
+pub fn update_cache(config: &mut GitGlobalConfig) -> (Vec<Repo>, Vec<Repo>) {
+    let mut live = Vec::new();
+    let mut removed = Vec::new();
+    for repo in config.get_cached_repos() {
+        if PathBuf::from(repo.path()).join(".git").exists() {
+            live.push(repo);
+        } else {
+            removed.push(repo);
+        }
+    }
+
+    let mut added = Vec::new();
+    let mut merged = live;
+    for repo in find_repos(config) {
+        if merged.iter().any(|known| known.path() == repo.path()) {
+            continue;
+        }
+        added.push(Repo::new(repo.path().to_string()));
+        merged.push(repo);
+    }
+    merged.sort_by(|a, b| a.path().cmp(&b.path()));
+
+    cache_repos(config, &merged);
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Creates an empty, unique temp directory for a test to use as its
+    /// `basedir`/cache location.
+    fn unique_temp_dir(label: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("git-global-test-{}-{}", std::process::id(), label));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Could not create temp dir for test.");
+        dir
+    }
+
+    /// Builds a `GitGlobalConfig` directly, bypassing `new()`'s XDG/git2
+    /// lookups, so tests can control `basedir`/`cache_file`/`cache_ttl`.
+    fn config_with(cache_file: PathBuf, cache_ttl: u64, basedir: &Path) -> GitGlobalConfig {
+        let basedir = basedir.to_str().unwrap().to_string();
+        GitGlobalConfig {
+            // Pass `None` rather than the real `core.excludesFile` so tests
+            // aren't influenced by whatever ambient git config the host
+            // running them happens to have.
+            ignore_matcher: GitGlobalConfig::build_ignore_matcher(&basedir, &Vec::new(), None),
+            basedir: basedir,
+            ignored_patterns: Vec::new(),
+            cache_file: cache_file,
+            cache_ttl: cache_ttl,
+            scan_threads: 1,
+            max_depth: 0,
+            repo_exclude_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn has_fresh_cache_is_false_without_a_cache_file() {
+        let dir = unique_temp_dir("no-cache");
+        let config = config_with(dir.join("repos.txt"), 3600, &dir);
+        assert!(!config.has_fresh_cache());
+    }
+
+    #[test]
+    fn has_fresh_cache_never_expires_when_ttl_is_zero() {
+        let dir = unique_temp_dir("ttl-zero");
+        let cache_file = dir.join("repos.txt");
+        fs::write(&cache_file, "").expect("Could not write cache file.");
+        let config = config_with(cache_file, 0, &dir);
+        assert!(config.has_fresh_cache());
+    }
+
+    #[test]
+    fn has_fresh_cache_expires_after_the_configured_ttl() {
+        let dir = unique_temp_dir("ttl-expiry");
+        let cache_file = dir.join("repos.txt");
+        fs::write(&cache_file, "").expect("Could not write cache file.");
+        let config = config_with(cache_file, 1, &dir);
+        assert!(config.has_fresh_cache());
+        thread::sleep(Duration::from_millis(1100));
+        assert!(!config.has_fresh_cache());
+    }
+
+    #[test]
+    fn ignore_pattern_is_a_glob_not_a_substring() {
+        let dir = unique_temp_dir("ignore-globs");
+        let matcher = GitGlobalConfig::build_ignore_matcher(
+            dir.to_str().unwrap(),
+            &vec!["node".to_string()],
+            None,
+        );
+
+        // "node" as a pattern should match a path segment named exactly
+        // "node", not any path that merely contains those letters.
+        assert!(
+            !matcher
+                .matched_path_or_any_parents(dir.join("my-node-app"), true)
+                .is_ignore(),
+            "\"node\" pattern should not match \"my-node-app\" as a substring"
+        );
+        assert!(matcher
+            .matched_path_or_any_parents(dir.join("node"), true)
+            .is_ignore());
+    }
+
+    #[test]
+    fn find_repos_by_name_matches_exact_or_fuzzy() {
+        let dir = unique_temp_dir("find-by-name");
+        let cache_file = dir.join("repos.txt");
+        let foo = dir.join("foo");
+        let foobar = dir.join("foobar");
+        // A cached path with no final component: exercises the
+        // `file_name()` `None` branch, which should just exclude it rather
+        // than panicking, under either match mode.
+        let root = PathBuf::from("/");
+
+        fs::write(
+            &cache_file,
+            format!(
+                "{}\n{}\n{}\n",
+                foo.to_str().unwrap(),
+                foobar.to_str().unwrap(),
+                root.to_str().unwrap(),
+            ),
+        )
+        .expect("Could not write cache file.");
+
+        let config = config_with(cache_file, 0, &dir);
+
+        let exact = config.find_repos_by_name("foo", false);
+        assert_eq!(exact.len(), 1);
+        assert_eq!(exact[0].path(), foo.to_str().unwrap());
+
+        let fuzzy = config.find_repos_by_name("foo", true);
+        let mut fuzzy_paths: Vec<String> = fuzzy.iter().map(|r| r.path().to_string()).collect();
+        fuzzy_paths.sort();
+        let mut expected = vec![
+            foo.to_str().unwrap().to_string(),
+            foobar.to_str().unwrap().to_string(),
+        ];
+        expected.sort();
+        assert_eq!(fuzzy_paths, expected);
+    }
+
+    #[test]
+    fn update_cache_reports_added_and_removed_repos() {
+        let dir = unique_temp_dir("update-cache");
+        let cache_file = dir.join("repos.txt");
+
+        // Cached but gone from disk: should be pruned and reported removed.
+        let gone_repo = dir.join("gone-repo");
+
+        // Cached and still on disk: should survive without being reported
+        // as newly "added" by the rescan.
+        let kept_repo = dir.join("kept-repo");
+        fs::create_dir_all(kept_repo.join(".git")).expect("Could not create kept repo.");
+
+        fs::write(
+            &cache_file,
+            format!(
+                "{}\n{}\n",
+                gone_repo.to_str().unwrap(),
+                kept_repo.to_str().unwrap()
+            ),
+        )
+        .expect("Could not write cache file.");
+
+        // Not in the stale cache, but present on disk: should be picked up
+        // by the rescan and reported added.
+        let new_repo = dir.join("new-repo");
+        fs::create_dir_all(new_repo.join(".git")).expect("Could not create new repo.");
+
+        let mut config = config_with(cache_file, 0, &dir);
+        let (added, removed) = update_cache(&mut config);
+
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].path(), gone_repo.to_str().unwrap());
+
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].path(), new_repo.to_str().unwrap());
+
+        let mut merged_paths: Vec<String> = config
+            .get_cached_repos()
+            .iter()
+            .map(|r| r.path().to_string())
+            .collect();
+        merged_paths.sort();
+        let mut expected = vec![
+            kept_repo.to_str().unwrap().to_string(),
+            new_repo.to_str().unwrap().to_string(),
+        ];
+        expected.sort();
+        assert_eq!(merged_paths, expected);
+    }
 }
\ No newline at end of file